@@ -1,11 +1,63 @@
 use std::collections::HashMap;
 use linked_hash_map::LinkedHashMap;
+use regex::Regex;
+use base64::{engine::general_purpose::STANDARD, Engine as _};
 
-pub fn convert_to_bytes(input: &str) -> Vec<u16> {
-    input.as_bytes().to_vec().iter().map(|x| *x as u16).collect()
+/// Token id type used throughout the crate.
+///
+/// The first 256 ids are reserved for raw bytes; learned BPE tokens are
+/// allocated above that. It is a `u32` (rather than the original `u16`) so
+/// realistic 50k–100k-token vocabularies fit without wrapping past 65 536.
+pub type TokenId = u32;
+
+/// Errors that training can surface for caller-supplied sizes, instead of
+/// panicking on valid-but-out-of-range input.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BpeError {
+    /// `vocab_size` was smaller than the first learnable id (`vocab_start`).
+    VocabSizeTooSmall { vocab_size: TokenId, vocab_start: TokenId },
+    /// Allocating the next token id would exceed the [`TokenId`] range.
+    TokenIdOverflow,
+}
+
+impl std::fmt::Display for BpeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BpeError::VocabSizeTooSmall { vocab_size, vocab_start } => write!(
+                f,
+                "vocab_size {vocab_size} must be at least vocab_start {vocab_start}"
+            ),
+            BpeError::TokenIdOverflow => write!(f, "token id overflowed the TokenId range"),
+        }
+    }
+}
+
+impl std::error::Error for BpeError {}
+
+/// cl100k-style split pattern used to pre-tokenize text before BPE runs.
+///
+/// It keeps common English contractions together, then splits runs of
+/// letters, digits and punctuation (each optionally preceded by a single
+/// space); any remaining run of whitespace becomes a chunk of its own. Because
+/// `\p{L}` and `\p{N}` require Unicode-aware matching it is compiled with the
+/// default (Unicode-enabled) `regex` engine.
+pub const GPT_SPLIT_PATTERN: &str = r"'s|'t|'re|'ve|'m|'ll|'d| ?\p{L}+| ?\p{N}+| ?[^\s\p{L}\p{N}]+|\s+";
+
+pub fn convert_to_bytes(input: &str) -> Vec<TokenId> {
+    input.as_bytes().to_vec().iter().map(|x| *x as TokenId).collect()
 }
 
-fn get_stats(ids: Vec<u16>) -> HashMap<(u16, u16), u16> {
+/// Splits `input` into pre-token chunks with [`GPT_SPLIT_PATTERN`] and returns
+/// the byte ids of each chunk.
+///
+/// Byte-pair merges are later run independently within each chunk so that a
+/// merge can never span a word, punctuation or whitespace boundary.
+pub fn pre_tokenize(input: &str) -> Vec<Vec<TokenId>> {
+    let re = Regex::new(GPT_SPLIT_PATTERN).expect("invalid split pattern");
+    re.find_iter(input).map(|m| convert_to_bytes(m.as_str())).collect()
+}
+
+fn get_stats(ids: Vec<TokenId>) -> HashMap<(TokenId, TokenId), TokenId> {
     let mut map = HashMap::new();
     for (n1, n2) in ids.iter().zip(ids.iter().skip(1)) {
         let count = map.entry((*n1, *n2)).or_insert(0);
@@ -14,6 +66,36 @@ fn get_stats(ids: Vec<u16>) -> HashMap<(u16, u16), u16> {
     map
 }
 
+/// Aggregates pair counts across every chunk without ever counting a pair that
+/// straddles two chunks.
+fn get_stats_chunks(chunks: &[Vec<TokenId>]) -> HashMap<(TokenId, TokenId), TokenId> {
+    let mut map = HashMap::new();
+    for chunk in chunks {
+        for (n1, n2) in chunk.iter().zip(chunk.iter().skip(1)) {
+            let count = map.entry((*n1, *n2)).or_insert(0);
+            *count += 1;
+        }
+    }
+    map
+}
+
+/// Chunk-aware variant of [`get_most_frequent_pair`] used by the regex-based
+/// training path.
+pub fn get_most_frequent_pair_chunks(chunks: &[Vec<TokenId>]) -> Option<(TokenId, TokenId)> {
+    let map = get_stats_chunks(chunks);
+    // Tie-break by the pair itself so the greedy choice is deterministic and
+    // matches the heap-based training path.
+    match map.iter().max_by_key(|&(pair, count)| (*count, *pair)) {
+        None => None,
+        Some((pair, count)) => {
+            if *count < 2 {
+                return None;
+            }
+            Some(*pair)
+        }
+    }
+}
+
 /// Finds the most frequent consecutive pair of elements in a sequence of u8.
 ///
 /// # Arguments
@@ -24,22 +106,25 @@ fn get_stats(ids: Vec<u16>) -> HashMap<(u16, u16), u16> {
 ///
 /// An `Option` containing the most frequent pair of elements as `(u8, u8)`.
 /// Returns `None` if the input vector is empty or has only one element.
-pub fn get_most_frequent_pair(ids: Vec<u16>) -> Option<(u16, u16)> {
-    let mut map = get_stats(ids);
-    let option = map.iter().max_by_key(|&(_, count)| count);
+pub fn get_most_frequent_pair(ids: Vec<TokenId>) -> Option<(TokenId, TokenId)> {
+    let map = get_stats(ids);
+    // Tie-break by the pair itself so equally-frequent pairs resolve the same
+    // way on every run (HashMap iteration order is otherwise nondeterministic)
+    // and agree with the heap-based training path.
+    let option = map.iter().max_by_key(|&(pair, count)| (*count, *pair));
     match option {
-        None => return None,
+        None => None,
         pair_count => {
             let (pair, count) = pair_count.unwrap();
             if *count < 2 {
                 return None;
             }
-            return Some(*pair);
+            Some(*pair)
         }
     }
 }
 
-pub fn merge(ids: Vec<u16>, pair: (u16, u16), idx: u16) -> Vec<u16> {
+pub fn merge(ids: Vec<TokenId>, pair: (TokenId, TokenId), idx: TokenId) -> Vec<TokenId> {
     if ids.len() < 2 {
         return ids;
     }
@@ -63,30 +148,174 @@ pub fn merge(ids: Vec<u16>, pair: (u16, u16), idx: u16) -> Vec<u16> {
     result
 }
 
-fn calculate_merges_default(ids: Vec<u16>, vocab_size: u16) -> LinkedHashMap<(u16, u16), u16> {
-    return calculate_merges(ids, vocab_size, 256);
+fn calculate_merges_default(ids: Vec<TokenId>, vocab_size: TokenId) -> Result<LinkedHashMap<(TokenId, TokenId), TokenId>, BpeError> {
+    calculate_merges(ids, vocab_size, 256)
 }
 
-fn calculate_merges(ids_orig: Vec<u16>, vocab_size: u16, vocab_start: u16) -> LinkedHashMap<(u16, u16), u16> {
+fn calculate_merges(ids_orig: Vec<TokenId>, vocab_size: TokenId, vocab_start: TokenId) -> Result<LinkedHashMap<(TokenId, TokenId), TokenId>, BpeError> {
+    if vocab_size < vocab_start {
+        return Err(BpeError::VocabSizeTooSmall { vocab_size, vocab_start });
+    }
     let num_merges = vocab_size - vocab_start;
     let mut ids = ids_orig.clone();
-    let mut idx: u16;
+    let mut idx: TokenId;
     let mut merges = LinkedHashMap::new();
     for i in 0..num_merges {
         let pair = get_most_frequent_pair(ids.clone());
         if pair.is_none() {
             break;
         }
-        idx = 256 + i;
+        idx = 256u32.checked_add(i).ok_or(BpeError::TokenIdOverflow)?;
         println!("merging {:?} into a new token {:?}", pair.unwrap(), idx);
         ids = merge(ids, pair.unwrap(), idx);
         merges.insert(pair.unwrap(), idx);
     }
-    merges
+    Ok(merges)
 }
 
-fn generate_vocab(merges: LinkedHashMap<(u16, u16), u16>) -> LinkedHashMap<u16, Vec<u8>> {
-    let mut vocab: LinkedHashMap<u16, Vec<u8>> = (0..256).map(|idx| (idx, vec![idx as u8])).collect();
+/// Incremental, heap-driven variant of [`calculate_merges`].
+///
+/// Rather than cloning the id vector and rebuilding the whole pair-count map on
+/// every step, it counts the pairs once (as [`get_stats`] does), keeps the counts in a max-heap
+/// and, after each merge, updates only the pairs touched locally: at every site
+/// where the merged pair occurred it decrements the two old neighbouring pairs
+/// and increments the two new pairs formed with the freshly minted token. The
+/// heap uses lazy deletion — a popped entry whose cached count no longer
+/// matches the live count is stale and skipped — so it never needs decrease-key
+/// while still making the same greedy choice as [`calculate_merges`].
+pub fn calculate_merges_heap(ids_orig: Vec<TokenId>, vocab_size: TokenId) -> Result<LinkedHashMap<(TokenId, TokenId), TokenId>, BpeError> {
+    use std::collections::BinaryHeap;
+
+    if vocab_size < 256 {
+        return Err(BpeError::VocabSizeTooSmall { vocab_size, vocab_start: 256 });
+    }
+    let num_merges = vocab_size - 256;
+
+    let n = ids_orig.len();
+    let mut tokens = ids_orig;
+    // Doubly linked list over the token positions so we can splice merges in
+    // place instead of rebuilding the vector.
+    let mut prev: Vec<Option<usize>> = (0..n).map(|i| if i == 0 { None } else { Some(i - 1) }).collect();
+    let mut next: Vec<Option<usize>> = (0..n).map(|i| if i + 1 < n { Some(i + 1) } else { None }).collect();
+    let mut alive = vec![true; n];
+
+    let mut counts: HashMap<(TokenId, TokenId), i64> = HashMap::new();
+    let mut sites: HashMap<(TokenId, TokenId), Vec<usize>> = HashMap::new();
+    let mut heap: BinaryHeap<(i64, (TokenId, TokenId))> = BinaryHeap::new();
+    for i in 0..n {
+        if let Some(j) = next[i] {
+            let pair = (tokens[i], tokens[j]);
+            *counts.entry(pair).or_insert(0) += 1;
+            sites.entry(pair).or_default().push(i);
+        }
+    }
+    for (pair, count) in counts.iter() {
+        heap.push((*count, *pair));
+    }
+
+    let mut merges = LinkedHashMap::new();
+    let mut minted = 0u32;
+    while minted < num_merges {
+        // Pop stale entries until a live maximum surfaces.
+        let pair = loop {
+            let (cached, pair) = match heap.pop() {
+                None => break None,
+                Some(top) => top,
+            };
+            if counts.get(&pair).copied().unwrap_or(0) == cached {
+                break Some(pair);
+            }
+        };
+        let pair = match pair {
+            None => break,
+            Some(pair) => pair,
+        };
+        if counts.get(&pair).copied().unwrap_or(0) < 2 {
+            break;
+        }
+
+        let idx = 256u32.checked_add(minted).ok_or(BpeError::TokenIdOverflow)?;
+        minted += 1;
+        merges.insert(pair, idx);
+        println!("merging {pair:?} into a new token {idx:?}");
+
+        let (a, b) = pair;
+        let occurrences = sites.remove(&pair).unwrap_or_default();
+        let mut touched: Vec<(TokenId, TokenId)> = Vec::new();
+        for i in occurrences {
+            // Re-validate: the site may have been consumed by an overlapping
+            // merge earlier in this same pass.
+            if !alive[i] {
+                continue;
+            }
+            let j = match next[i] {
+                Some(j) if alive[j] && tokens[i] == a && tokens[j] == b => j,
+                _ => continue,
+            };
+            *counts.entry(pair).or_insert(0) -= 1;
+
+            if let Some(p) = prev[i] {
+                let left = (tokens[p], a);
+                *counts.entry(left).or_insert(0) -= 1;
+                touched.push(left);
+                let new_left = (tokens[p], idx);
+                *counts.entry(new_left).or_insert(0) += 1;
+                sites.entry(new_left).or_default().push(p);
+                touched.push(new_left);
+            }
+            if let Some(k) = next[j] {
+                let right = (b, tokens[k]);
+                *counts.entry(right).or_insert(0) -= 1;
+                touched.push(right);
+                let new_right = (idx, tokens[k]);
+                *counts.entry(new_right).or_insert(0) += 1;
+                sites.entry(new_right).or_default().push(i);
+                touched.push(new_right);
+            }
+
+            // Splice j out and turn position i into the merged token.
+            tokens[i] = idx;
+            alive[j] = false;
+            next[i] = next[j];
+            if let Some(k) = next[j] {
+                prev[k] = Some(i);
+            }
+        }
+        for pair in touched {
+            if let Some(count) = counts.get(&pair) {
+                heap.push((*count, pair));
+            }
+        }
+    }
+    Ok(merges)
+}
+
+/// Trains a merge table the way production tokenizers do: the input is first
+/// split into pre-token chunks with [`pre_tokenize`] and every merge step then
+/// picks the most frequent pair across all chunks and applies it within each
+/// chunk, so no learned token ever spans a chunk boundary.
+pub fn calculate_merges_regex(input: &str, vocab_size: TokenId) -> Result<LinkedHashMap<(TokenId, TokenId), TokenId>, BpeError> {
+    if vocab_size < 256 {
+        return Err(BpeError::VocabSizeTooSmall { vocab_size, vocab_start: 256 });
+    }
+    let num_merges = vocab_size - 256;
+    let mut chunks = pre_tokenize(input);
+    let mut merges = LinkedHashMap::new();
+    for i in 0..num_merges {
+        let pair = match get_most_frequent_pair_chunks(&chunks) {
+            None => break,
+            Some(pair) => pair,
+        };
+        let idx = 256u32.checked_add(i).ok_or(BpeError::TokenIdOverflow)?;
+        println!("merging {:?} into a new token {:?}", pair, idx);
+        chunks = chunks.into_iter().map(|chunk| merge(chunk, pair, idx)).collect();
+        merges.insert(pair, idx);
+    }
+    Ok(merges)
+}
+
+fn generate_vocab(merges: LinkedHashMap<(TokenId, TokenId), TokenId>) -> LinkedHashMap<TokenId, Vec<u8>> {
+    let mut vocab: LinkedHashMap<TokenId, Vec<u8>> = (0..256).map(|idx| (idx, vec![idx as u8])).collect();
     for ((p0, p1), idx) in merges.iter() {
         let val0 = vocab.get(p0).expect("p0 not found");
         let val1 = vocab.get(p1).expect("p0 not found");
@@ -95,12 +324,133 @@ fn generate_vocab(merges: LinkedHashMap<(u16, u16), u16>) -> LinkedHashMap<u16,
     vocab
 }
 
-fn generate_and_decode(ids_orig: Vec<u16>, merges: LinkedHashMap<(u16, u16), u16>) -> String {
+/// Builds a vocab that also renders the reserved special-token ids back to
+/// their literal strings, so [`decode`] can round-trip a stream produced by
+/// [`encode_with_special_tokens`].
+pub fn generate_vocab_with_special_tokens(
+    merges: LinkedHashMap<(TokenId, TokenId), TokenId>,
+    special: &HashMap<String, TokenId>,
+) -> LinkedHashMap<TokenId, Vec<u8>> {
+    let mut vocab = generate_vocab(merges);
+    for (token, idx) in special.iter() {
+        vocab.insert(*idx, token.as_bytes().to_vec());
+    }
+    vocab
+}
+
+/// Encodes `input` while preserving reserved special tokens.
+///
+/// The text is scanned for every special-token substring; the ordinary BPE
+/// merges are run only on the segments in between, and each special marker is
+/// emitted verbatim as its reserved id, never merged with its neighbours.
+pub fn encode_with_special_tokens(
+    input: &str,
+    merges: LinkedHashMap<(TokenId, TokenId), TokenId>,
+    special: &HashMap<String, TokenId>,
+) -> Vec<TokenId> {
+    // Longest markers first so that e.g. `<|endoftext|>` is matched before any
+    // shorter marker that happens to be a prefix of it.
+    let mut markers: Vec<(&String, &TokenId)> = special.iter().collect();
+    markers.sort_by_key(|(token, _)| std::cmp::Reverse(token.len()));
+
+    let mut result = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let hit = markers
+            .iter()
+            .filter_map(|(token, idx)| rest.find(token.as_str()).map(|pos| (pos, *token, *idx)))
+            .min_by_key(|(pos, token, _)| (*pos, std::cmp::Reverse(token.len())));
+        match hit {
+            Some((pos, token, idx)) => {
+                if pos > 0 {
+                    result.extend(encode_tokens(convert_to_bytes(&rest[..pos]), &merges));
+                }
+                result.push(*idx);
+                rest = &rest[pos + token.len()..];
+            }
+            None => {
+                result.extend(encode_tokens(convert_to_bytes(rest), &merges));
+                break;
+            }
+        }
+    }
+    result
+}
+
+/// Serialises a merge table to the tiktoken `.tiktoken` rank-file format.
+///
+/// Each line is `base64(token_bytes) rank`, where the rank is the token id, so
+/// the byte sequences produced by [`generate_vocab`] round-trip with the rank
+/// files shipped alongside pretrained tiktoken vocabularies.
+pub fn save_tiktoken(merges: LinkedHashMap<(TokenId, TokenId), TokenId>) -> String {
+    let vocab = generate_vocab(merges);
+    let mut entries: Vec<(TokenId, Vec<u8>)> = vocab.into_iter().collect();
+    entries.sort_by_key(|(idx, _)| *idx);
+    let mut out = String::new();
+    for (idx, bytes) in entries {
+        out.push_str(&STANDARD.encode(&bytes));
+        out.push(' ');
+        out.push_str(&idx.to_string());
+        out.push('\n');
+    }
+    out
+}
+
+/// Parses a tiktoken rank file back into a merge table.
+///
+/// Each line is decoded into `(token_bytes, rank)`. Raw single bytes keep the
+/// first 256 ids; every longer token is reconstructed by finding its two
+/// lower-rank children. A token can have several in-vocab splits, so — like
+/// minbpe's `recover_merges` — we pick the split that minimises the larger of
+/// the two child ranks, which is the pair BPE must have merged last to mint it.
+pub fn load_tiktoken(contents: &str) -> LinkedHashMap<(TokenId, TokenId), TokenId> {
+    let mut ranks: HashMap<Vec<u8>, TokenId> = HashMap::new();
+    let mut tokens: Vec<(Vec<u8>, TokenId)> = Vec::new();
+    for line in contents.lines() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut parts = line.split_whitespace();
+        let encoded = parts.next().expect("missing token column");
+        let rank: TokenId = parts.next().expect("missing rank column").parse().expect("invalid rank");
+        let bytes = STANDARD.decode(encoded).expect("invalid base64 token");
+        ranks.insert(bytes.clone(), rank);
+        tokens.push((bytes, rank));
+    }
+    tokens.sort_by_key(|(_, rank)| *rank);
+
+    let mut merges = LinkedHashMap::new();
+    for (bytes, rank) in tokens {
+        if bytes.len() < 2 {
+            continue;
+        }
+        let mut best: Option<(TokenId, (TokenId, TokenId))> = None;
+        for split in 1..bytes.len() {
+            let left = &bytes[..split];
+            let right = &bytes[split..];
+            if let (Some(&l), Some(&r)) = (ranks.get(left), ranks.get(right)) {
+                if l < rank && r < rank {
+                    let larger = l.max(r);
+                    if best.is_none_or(|(best_larger, _)| larger < best_larger) {
+                        best = Some((larger, (l, r)));
+                    }
+                }
+            }
+        }
+        if let Some((_, pair)) = best {
+            merges.insert(pair, rank);
+        }
+    }
+    merges
+}
+
+fn generate_and_decode(ids_orig: Vec<TokenId>, merges: LinkedHashMap<(TokenId, TokenId), TokenId>) -> String {
     let vocab = generate_vocab(merges);
     decode(ids_orig, vocab)
 }
 
-fn decode(ids: Vec<u16>, vocab: LinkedHashMap<u16, Vec<u8>>) -> String {
+fn decode(ids: Vec<TokenId>, vocab: LinkedHashMap<TokenId, Vec<u8>>) -> String {
     let mut res: Vec<u8> = Vec::new();
     for idx in ids.iter() {
         let value = vocab.get(idx).expect("idx not found");
@@ -109,34 +459,187 @@ fn decode(ids: Vec<u16>, vocab: LinkedHashMap<u16, Vec<u8>>) -> String {
     String::from_utf8_lossy(&res).to_string()
 }
 
-fn get_pair_with_lowest_value(stats: HashMap<(u16, u16), u16>, merges: &LinkedHashMap<(u16, u16), u16>) -> (u16, u16) {
-    let mut min = std::u16::MAX;
-    let mut min_pair = (0, 0);
-    for (pair, count) in stats.iter() {
-        let res = merges.get(pair);
-        if res.is_some() {
-            let code = *res.unwrap();
-            if code < min {
-                min = code;
-                min_pair = *pair;
+/// Linked-parts byte-pair merge.
+///
+/// Instead of rescanning the whole sequence on every step, it keeps a `ranks`
+/// vector giving each adjacent pair its merge id (or `None`, meaning the pair
+/// must never be merged), repeatedly merges the lowest-ranked pair and then
+/// refreshes only the (at most two) ranks adjacent to the merge point. It
+/// produces byte-for-byte the same token ids as the previous quadratic loop,
+/// which always merged the lowest-id pair first.
+fn byte_pair_merge(mut parts: Vec<TokenId>, merges: &LinkedHashMap<(TokenId, TokenId), TokenId>) -> Vec<TokenId> {
+    let rank = |parts: &Vec<TokenId>, i: usize| -> Option<TokenId> {
+        if i + 1 < parts.len() {
+            merges.get(&(parts[i], parts[i + 1])).copied()
+        } else {
+            None
+        }
+    };
+    if parts.len() < 2 {
+        return parts;
+    }
+    let mut ranks: Vec<Option<TokenId>> = (0..parts.len()).map(|i| rank(&parts, i)).collect();
+    loop {
+        let min = ranks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, r)| r.map(|rank| (rank, i)))
+            .min();
+        let (min_rank, min_pos) = match min {
+            None => break,
+            Some(min) => min,
+        };
+        parts[min_pos] = min_rank;
+        parts.remove(min_pos + 1);
+        ranks.remove(min_pos + 1);
+        ranks[min_pos] = rank(&parts, min_pos);
+        if min_pos > 0 {
+            ranks[min_pos - 1] = rank(&parts, min_pos - 1);
+        }
+    }
+    parts
+}
+
+/// Source encoding a raw byte buffer may be in before it is transcoded to the
+/// UTF-8 the rest of the pipeline assumes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceEncoding {
+    Utf8,
+    Latin1,
+    Windows1252,
+}
+
+/// The five byte values that are undefined in Windows-1252.
+const CP1252_UNDEFINED: [u8; 5] = [0x81, 0x8D, 0x8F, 0x90, 0x9D];
+
+/// The Unicode scalars Windows-1252 places in the 0x80–0x9F range, which
+/// Latin-1 would instead map to C1 control characters.
+const CP1252_HIGH: [u32; 32] = [
+    0x20AC, 0x0081, 0x201A, 0x0192, 0x201E, 0x2026, 0x2020, 0x2021,
+    0x02C6, 0x2030, 0x0160, 0x2039, 0x0152, 0x008D, 0x017D, 0x008F,
+    0x0090, 0x2018, 0x2019, 0x201C, 0x201D, 0x2022, 0x2013, 0x2014,
+    0x02DC, 0x2122, 0x0161, 0x203A, 0x0153, 0x009D, 0x017E, 0x0178,
+];
+
+/// Decodes `bytes` under `encoding`, returning `None` when the buffer contains
+/// a byte sequence that is invalid for that encoding.
+fn transcode_to_utf8(bytes: &[u8], encoding: SourceEncoding) -> Option<String> {
+    match encoding {
+        SourceEncoding::Utf8 => String::from_utf8(bytes.to_vec()).ok(),
+        SourceEncoding::Latin1 => Some(bytes.iter().map(|b| *b as char).collect()),
+        SourceEncoding::Windows1252 => {
+            if bytes.iter().any(|b| CP1252_UNDEFINED.contains(b)) {
+                return None;
             }
+            Some(
+                bytes
+                    .iter()
+                    .map(|b| match *b {
+                        0x80..=0x9F => char::from_u32(CP1252_HIGH[(*b - 0x80) as usize]).unwrap(),
+                        other => other as char,
+                    })
+                    .collect(),
+            )
         }
     }
-    min_pair
 }
 
-fn encode(input: &str, merges: LinkedHashMap<(u16, u16), u16>) -> Vec<u16> {
-    let mut tokens = convert_to_bytes(input);
-    while tokens.len() > 1 {
-        let stats = get_stats(tokens.clone());
-        let pair = get_pair_with_lowest_value(stats, &merges);
-        if !merges.contains_key(&pair) {
-            break;
+/// Counts how many distinct 128-code-point ranges (a coarse stand-in for
+/// Unicode blocks) the non-ASCII characters of `text` fall into. A lower count means the characters cluster in a few related
+/// ranges; a high count means they scatter, which is the tell-tale of a wrong
+/// encoding guess (mojibake sprays characters across unrelated blocks).
+fn block_scatter(text: &str) -> usize {
+    let mut blocks = std::collections::HashSet::new();
+    for c in text.chars() {
+        let cp = c as u32;
+        if cp > 0x7F {
+            blocks.insert(cp >> 7);
         }
-        let idx = merges.get(&pair).unwrap();
-        tokens = merge(tokens, pair, *idx);
     }
-    tokens
+    blocks.len()
+}
+
+/// Sniffs the most likely source encoding of `bytes`.
+///
+/// Each candidate that can decode the buffer is scored by [`block_scatter`];
+/// the least-scattered candidate wins, with ties broken in favour of UTF-8,
+/// then Windows-1252, then Latin-1 (which decodes any byte and so is always the
+/// final fallback).
+pub fn detect_encoding(bytes: &[u8]) -> SourceEncoding {
+    let candidates = [
+        SourceEncoding::Utf8,
+        SourceEncoding::Windows1252,
+        SourceEncoding::Latin1,
+    ];
+    let mut best: Option<((usize, usize), SourceEncoding)> = None;
+    for (order, encoding) in candidates.iter().enumerate() {
+        if let Some(text) = transcode_to_utf8(bytes, *encoding) {
+            let key = (block_scatter(&text), order);
+            if best.is_none_or(|(best_key, _)| key < best_key) {
+                best = Some((key, *encoding));
+            }
+        }
+    }
+    best.map(|(_, encoding)| encoding).unwrap_or(SourceEncoding::Latin1)
+}
+
+/// Transcodes UTF-8 `text` back into `encoding`, the inverse of
+/// [`transcode_to_utf8`]. Characters with no representation in the target
+/// encoding are dropped, mirroring the lossy spirit of [`decode`].
+fn transcode_from_utf8(text: &str, encoding: SourceEncoding) -> Vec<u8> {
+    match encoding {
+        SourceEncoding::Utf8 => text.as_bytes().to_vec(),
+        SourceEncoding::Latin1 => text
+            .chars()
+            .filter_map(|c| u8::try_from(c as u32).ok())
+            .collect(),
+        SourceEncoding::Windows1252 => text
+            .chars()
+            .filter_map(|c| {
+                let cp = c as u32;
+                if let Some(offset) = CP1252_HIGH.iter().position(|h| *h == cp) {
+                    Some(0x80 + offset as u8)
+                } else {
+                    u8::try_from(cp).ok().filter(|b| !(0x80..=0x9F).contains(b))
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Decodes token ids and transcodes the result back into `encoding`, so a
+/// corpus ingested with [`convert_to_bytes_detect`] can round-trip to its
+/// original byte representation.
+pub fn decode_to_encoding(ids: Vec<TokenId>, vocab: LinkedHashMap<TokenId, Vec<u8>>, encoding: SourceEncoding) -> Vec<u8> {
+    transcode_from_utf8(&decode(ids, vocab), encoding)
+}
+
+/// Ingestion entry point: detect the source encoding of a raw byte buffer,
+/// transcode it to UTF-8 and return both the byte ids and the detected
+/// encoding so the caller can later transcode decoded output back.
+pub fn convert_to_bytes_detect(bytes: &[u8]) -> (Vec<TokenId>, SourceEncoding) {
+    let encoding = detect_encoding(bytes);
+    let text = transcode_to_utf8(bytes, encoding).expect("detected encoding failed to decode");
+    (convert_to_bytes(&text), encoding)
+}
+
+fn encode_tokens(tokens: Vec<TokenId>, merges: &LinkedHashMap<(TokenId, TokenId), TokenId>) -> Vec<TokenId> {
+    byte_pair_merge(tokens, merges)
+}
+
+fn encode(input: &str, merges: LinkedHashMap<(TokenId, TokenId), TokenId>) -> Vec<TokenId> {
+    encode_tokens(convert_to_bytes(input), &merges)
+}
+
+/// Regex-aware counterpart to [`encode`]: applies the learned merges
+/// chunk-by-chunk so encoding matches the chunk boundaries used during
+/// [`calculate_merges_regex`] training.
+pub fn encode_regex(input: &str, merges: LinkedHashMap<(TokenId, TokenId), TokenId>) -> Vec<TokenId> {
+    let mut result = Vec::new();
+    for chunk in pre_tokenize(input) {
+        result.extend(encode_tokens(chunk, &merges));
+    }
+    result
 }
 
 #[cfg(test)]
@@ -172,7 +675,7 @@ mod tests {
     fn get_most_frequent_pair_complex() {
         let ids = convert_to_bytes(LONG_INPUT);
         let map = get_most_frequent_pair(ids);
-        assert_eq!(map, Some((101u16, 32u16)), "The most frequent pair did not match the expected values.");
+        assert_eq!(map, Some((101u32, 32u32)), "The most frequent pair did not match the expected values.");
     }
 
     #[test]
@@ -202,14 +705,100 @@ mod tests {
         println!("Merged: {:?}", merged);
     }
 
+    #[test]
+    fn detect_encoding_ascii_is_utf8() {
+        assert_eq!(detect_encoding(b"hello world"), SourceEncoding::Utf8);
+    }
+
+    #[test]
+    fn detect_encoding_latin1_round_trips() {
+        // 0xE9 is 'é' in Latin-1/Windows-1252 but an invalid lone UTF-8 lead
+        // byte, so UTF-8 is rejected and a single-block candidate wins.
+        let raw = b"caf\xe9";
+        let encoding = detect_encoding(raw);
+        assert_ne!(encoding, SourceEncoding::Utf8);
+        let (_ids, detected) = convert_to_bytes_detect(raw);
+        let text = transcode_to_utf8(raw, detected).unwrap();
+        assert_eq!(text, "café");
+        assert_eq!(transcode_from_utf8(&text, detected), raw);
+    }
+
+    #[test]
+    fn decode_to_encoding_round_trips_latin1() {
+        let raw = b"caf\xe9";
+        let (ids, detected) = convert_to_bytes_detect(raw);
+        // With no learned merges the vocab is just the raw bytes, so decoding
+        // back under the detected encoding must reproduce the original buffer.
+        let vocab = generate_vocab(LinkedHashMap::new());
+        assert_eq!(decode_to_encoding(ids, vocab, detected), raw);
+    }
+
+    #[test]
+    fn pre_tokenize_splits_on_boundaries() {
+        let chunks = pre_tokenize("hello world's");
+        let decoded: Vec<String> = chunks
+            .iter()
+            .map(|c| String::from_utf8_lossy(&c.iter().map(|x| *x as u8).collect::<Vec<u8>>()).to_string())
+            .collect();
+        assert_eq!(decoded, vec!["hello", " world", "'s"]);
+    }
+
+    #[test]
+    fn encode_regex_never_crosses_spaces() {
+        let input = "aa aa";
+        let merges = calculate_merges_regex("aa aa aa", 258).unwrap();
+        let vocab = generate_vocab(merges.clone());
+        let encoded = encode_regex(input, merges);
+
+        // The real invariant: a learned token may bundle a leading space with
+        // its word (` ?\p{L}+`), but no token may ever straddle the boundary
+        // between two pre-token chunks.
+        let mut boundaries = std::collections::HashSet::new();
+        let mut acc = 0usize;
+        for chunk in pre_tokenize(input) {
+            acc += chunk.len();
+            boundaries.insert(acc);
+        }
+        let mut pos = 0usize;
+        for token in &encoded {
+            let len = vocab.get(token).expect("token not in vocab").len();
+            for inner in (pos + 1)..(pos + len) {
+                assert!(!boundaries.contains(&inner), "a learned token spans a chunk boundary");
+            }
+            pos += len;
+        }
+    }
+
+    #[test]
+    fn calculate_merges_rejects_small_vocab() {
+        let ids = convert_to_bytes("aaabdaaabac");
+        let err = calculate_merges_default(ids, 100).unwrap_err();
+        assert_eq!(err, BpeError::VocabSizeTooSmall { vocab_size: 100, vocab_start: 256 });
+    }
+
     #[test]
     fn calculate_merges_simple() {
         let input = "aaabdaaabac";
         let ids = convert_to_bytes(input);
-        let result = calculate_merges_default(ids, 276);
+        let result = calculate_merges_default(ids, 276).unwrap();
         assert_eq!(result.len(), 3);
     }
 
+    #[test]
+    fn calculate_merges_heap_matches_default() {
+        let ids = convert_to_bytes("aaabdaaabac");
+        let default = calculate_merges_default(ids.clone(), 276).unwrap();
+        let heap = calculate_merges_heap(ids, 276).unwrap();
+
+        // Both paths tie-break by (count, pair), so the greedy choice is
+        // deterministic — pin the exact expected table rather than trusting a
+        // nondeterministic reference.
+        let expected: LinkedHashMap<(TokenId, TokenId), TokenId> =
+            [((97, 97), 256), ((256, 97), 257), ((257, 98), 258)].into_iter().collect();
+        assert_eq!(default, expected);
+        assert_eq!(heap, expected);
+    }
+
     #[test]
     fn calculate_merges_complex() {
         let merges = run_calculate_merges();
@@ -238,6 +827,18 @@ mod tests {
         println!("Encoded: {:?}", encoded);
     }
 
+    #[test]
+    fn encode_decode_special_tokens() {
+        let merges = run_calculate_merges();
+        let mut special = HashMap::new();
+        special.insert("<|endoftext|>".to_string(), 1000u32);
+        let encoded = encode_with_special_tokens("hello<|endoftext|>world", merges.clone(), &special);
+        assert!(encoded.contains(&1000u32), "the reserved id must be emitted verbatim");
+        let vocab = generate_vocab_with_special_tokens(merges, &special);
+        let decoded = decode(encoded, vocab);
+        assert_eq!(decoded, "hello<|endoftext|>world");
+    }
+
     #[test]
     fn encode_decode_simple() {
         let merges = run_calculate_merges();
@@ -251,10 +852,30 @@ mod tests {
         assert_eq!(res, orig_str);
     }
 
-    fn run_calculate_merges() -> LinkedHashMap<(u16, u16), u16> {
+    #[test]
+    fn tiktoken_round_trip() {
+        let merges = run_calculate_merges();
+        let serialized = save_tiktoken(merges.clone());
+        let reloaded = load_tiktoken(&serialized);
+        assert_eq!(reloaded, merges);
+    }
+
+    #[test]
+    fn load_tiktoken_picks_provenance_split() {
+        // "abc" (rank 258) has two in-vocab splits: (a, bc) with larger child
+        // 257 and (ab, c) with larger child 256. The provenance split is the
+        // one minimising the larger child rank, so the merge must be (256, 99),
+        // not the first-encountered (97, 257).
+        let contents = "YQ== 97\nYg== 98\nYw== 99\nYWI= 256\nYmM= 257\nYWJj 258\n";
+        let merges = load_tiktoken(contents);
+        assert_eq!(merges.get(&(256, 99)), Some(&258));
+        assert_eq!(merges.get(&(97, 257)), None);
+    }
+
+    fn run_calculate_merges() -> LinkedHashMap<(TokenId, TokenId), TokenId> {
         let input = read_complex_file();
         let ids = convert_to_bytes(&input);
-        let merges: LinkedHashMap<(u16, u16), u16> = calculate_merges_default(ids, 276);
+        let merges: LinkedHashMap<(TokenId, TokenId), TokenId> = calculate_merges_default(ids, 276).unwrap();
         merges
     }
 